@@ -0,0 +1,71 @@
+//! End-of-line conversion for `--ascii`.
+
+use std::io::{self, Read};
+
+/// Line ending written when decompressing with `--ascii`.
+pub const LOCAL_EOL: &[u8] = if cfg!(windows) { b"\r\n" } else { b"\n" };
+
+/// Reads from `inner` with every LF or CRLF line ending replaced by `eol`.
+/// A lone CR is passed through unchanged.
+pub struct Ascii<R> {
+    inner: R,
+    eol: &'static [u8],
+    raw: Vec<u8>,
+    converted: Vec<u8>,
+    pos: usize,
+    /// The last byte read was a CR that may start a CRLF.
+    cr: bool,
+}
+
+impl<R: Read> Ascii<R> {
+    pub fn new(inner: R, eol: &'static [u8]) -> Self {
+        Ascii {
+            inner,
+            eol,
+            raw: vec![0; 32 * 1024],
+            converted: Vec::new(),
+            pos: 0,
+            cr: false,
+        }
+    }
+
+    /// Convert the next chunk of input, leaving `converted` empty at EOF.
+    fn fill(&mut self) -> io::Result<()> {
+        self.converted.clear();
+        self.pos = 0;
+        while self.converted.is_empty() {
+            let n = self.inner.read(&mut self.raw)?;
+            if n == 0 {
+                if self.cr {
+                    self.cr = false;
+                    self.converted.push(b'\r');
+                }
+                return Ok(());
+            }
+            for &byte in &self.raw[..n] {
+                if self.cr && byte != b'\n' {
+                    self.converted.push(b'\r');
+                }
+                self.cr = byte == b'\r';
+                match byte {
+                    b'\r' => {}
+                    b'\n' => self.converted.extend_from_slice(self.eol),
+                    _ => self.converted.push(byte),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Ascii<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.converted.len() {
+            self.fill()?;
+        }
+        let n = buf.len().min(self.converted.len() - self.pos);
+        buf[..n].copy_from_slice(&self.converted[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,298 @@
+//! Choosing how to decode an input for `--decompress`.
+
+use std::io::{self, BufRead, Chain, Cursor, Read};
+
+use flate2::bufread::GzDecoder;
+use flate2::{Decompress, FlushDecompress, GzHeader, Status};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A decoder for one input, picked from its first bytes.
+pub enum Decoder<R: BufRead> {
+    Gzip(Box<Members<R>>),
+    /// A zlib or raw deflate stream.
+    Inflate(Inflate<R>),
+    /// Not compressed at all; passed through as is.
+    Copy(R),
+}
+
+impl<R: BufRead> Decoder<R> {
+    /// Pick a decoder for `input`. Anything but gzip is only accepted with
+    /// `force`: zlib and raw deflate streams are decoded, and, as in GNU
+    /// gzip, other data is copied unchanged when writing to stdout. There,
+    /// as `zcat -f` must pass text through, a zlib or deflate stream is only
+    /// recognized if it ends within the first buffer of input.
+    pub fn new(mut input: R, force: bool, to_stdout: bool) -> io::Result<Self> {
+        let copy = force && to_stdout;
+        let prefix = input.fill_buf()?;
+        // Empty or truncated input is left for the gzip decoder to report,
+        // unless it can be copied.
+        let n = prefix.len().min(GZIP_MAGIC.len());
+        Ok(if copy && n < GZIP_MAGIC.len() {
+            Decoder::Copy(input)
+        } else if prefix[..n] == GZIP_MAGIC[..n] {
+            Decoder::Gzip(Box::new(Members::new(input, copy)))
+        } else if force && is_zlib(prefix) && (!copy || inflates(prefix, true, false)) {
+            Decoder::Inflate(Inflate::new(input, true))
+        } else if force && inflates(prefix, false, !copy) {
+            Decoder::Inflate(Inflate::new(input, false))
+        } else if copy {
+            Decoder::Copy(input)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not in gzip format",
+            ));
+        })
+    }
+
+    /// The gzip header, once it has been read.
+    pub fn header(&self) -> Option<&GzHeader> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.header(),
+            _ => None,
+        }
+    }
+
+    /// What followed the last gzip member, once the input has been read to
+    /// the end.
+    pub fn trailing(&self) -> Trailing {
+        match self {
+            Decoder::Gzip(decoder) => decoder.trailing,
+            _ => Trailing::Nothing,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            Decoder::Inflate(decoder) => decoder.read(buf),
+            Decoder::Copy(input) => input.read(buf),
+        }
+    }
+}
+
+/// Bytes that didn't form another member after the last one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trailing {
+    Nothing,
+    Zeros,
+    Garbage,
+}
+
+/// The input, behind any bytes taken off it to look for another member.
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Decodes gzip members one after another, stopping quietly at anything
+/// after the last that isn't another member, as GNU gzip does.
+pub struct Members<R: BufRead> {
+    state: State<R>,
+    /// Header of the first member, kept once later ones start.
+    header: Option<GzHeader>,
+    /// Pass what follows the last member through instead of dropping it.
+    copy_trailing: bool,
+    trailing: Trailing,
+}
+
+enum State<R: BufRead> {
+    Member(Box<GzDecoder<Peeked<R>>>),
+    Copy(Peeked<R>),
+    Done,
+}
+
+impl<R: BufRead> Members<R> {
+    fn new(input: R, copy_trailing: bool) -> Self {
+        Members {
+            state: State::Member(Box::new(GzDecoder::new(
+                Cursor::new(Vec::new()).chain(input),
+            ))),
+            header: None,
+            copy_trailing,
+            trailing: Trailing::Nothing,
+        }
+    }
+
+    fn header(&self) -> Option<&GzHeader> {
+        match &self.state {
+            State::Member(member) if self.header.is_none() => member.header(),
+            _ => self.header.as_ref(),
+        }
+    }
+
+    /// Decide what to do with the input left after a member.
+    fn next(&mut self, mut input: R) -> io::Result<State<R>> {
+        let prefix = take_prefix(&mut input, GZIP_MAGIC.len())?;
+        if prefix.is_empty() {
+            return Ok(State::Done);
+        }
+        let is_member = prefix == GZIP_MAGIC;
+        let mut input = Cursor::new(prefix).chain(input);
+        if is_member {
+            return Ok(State::Member(Box::new(GzDecoder::new(input))));
+        }
+        if self.copy_trailing {
+            return Ok(State::Copy(input));
+        }
+
+        let (mut len, mut zeros) = (0, true);
+        loop {
+            let buf = input.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            zeros &= buf.iter().all(|&b| b == 0);
+            len += buf.len();
+            let n = buf.len();
+            input.consume(n);
+        }
+        // A lone non-zero byte reads as a truncated magic number.
+        if len == 1 && !zeros {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of file",
+            ));
+        }
+        self.trailing = if zeros {
+            Trailing::Zeros
+        } else {
+            Trailing::Garbage
+        };
+        Ok(State::Done)
+    }
+}
+
+/// Take `len` bytes off `input`, or fewer at the end of the input, to look
+/// at the start of a member. `fill_buf` alone won't do, as they may
+/// straddle the end of the buffer.
+pub fn take_prefix<R: BufRead>(input: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(len);
+    while prefix.len() < len {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let n = buf.len().min(len - prefix.len());
+        prefix.extend_from_slice(&buf[..n]);
+        input.consume(n);
+    }
+    Ok(prefix)
+}
+
+impl<R: BufRead> Read for Members<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match &mut self.state {
+                State::Member(member) => {
+                    let n = member.read(buf)?;
+                    if n > 0 || buf.is_empty() {
+                        return Ok(n);
+                    }
+                }
+                State::Copy(input) => return input.read(buf),
+                State::Done => return Ok(0),
+            }
+            let State::Member(member) = std::mem::replace(&mut self.state, State::Done) else {
+                unreachable!();
+            };
+            if self.header.is_none() {
+                self.header = member.header().cloned();
+            }
+            let (_, input) = member.into_inner().into_inner();
+            self.state = self.next(input)?;
+        }
+    }
+}
+
+/// Decodes a single zlib or raw deflate stream, treating input that ends
+/// before the stream does as an error. flate2's own decoders just stop
+/// there, which would pass off a truncated file as complete.
+pub struct Inflate<R: BufRead> {
+    input: R,
+    inflate: Decompress,
+    done: bool,
+}
+
+impl<R: BufRead> Inflate<R> {
+    fn new(input: R, zlib_header: bool) -> Self {
+        Inflate {
+            input,
+            inflate: Decompress::new(zlib_header),
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let input = self.input.fill_buf()?;
+            let eof = input.is_empty();
+            let (before_in, before_out) = (self.inflate.total_in(), self.inflate.total_out());
+            let status = self
+                .inflate
+                .decompress(input, buf, FlushDecompress::None)
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "corrupt deflate stream")
+                })?;
+            let consumed = (self.inflate.total_in() - before_in) as usize;
+            let written = (self.inflate.total_out() - before_out) as usize;
+            self.input.consume(consumed);
+
+            if status == Status::StreamEnd {
+                self.done = true;
+                return Ok(written);
+            }
+            if written > 0 {
+                return Ok(written);
+            }
+            if eof {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of file",
+                ));
+            }
+            if consumed == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt deflate stream",
+                ));
+            }
+        }
+    }
+}
+
+/// Whether `prefix` starts with an RFC 1950 header for a deflate stream.
+fn is_zlib(prefix: &[u8]) -> bool {
+    match prefix {
+        [cmf, flg, ..] => {
+            cmf & 0x0f == 8 && cmf >> 4 <= 7 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0
+        }
+        _ => false,
+    }
+}
+
+/// Whether `prefix` decodes as a deflate stream, with a zlib header if
+/// `zlib_header`, without error. Text and most other data fail within the
+/// first few bytes. A prefix that runs out before the stream ends is only
+/// accepted if `partial`, since more input may follow; if it doesn't,
+/// `Inflate` reports the truncation.
+fn inflates(prefix: &[u8], zlib_header: bool, partial: bool) -> bool {
+    let mut inflate = Decompress::new(zlib_header);
+    let mut out = Vec::with_capacity(32 * 1024);
+    loop {
+        out.clear();
+        let consumed = inflate.total_in() as usize;
+        match inflate.decompress_vec(&prefix[consumed..], &mut out, FlushDecompress::None) {
+            Ok(Status::StreamEnd) => return true,
+            Ok(_) if inflate.total_in() as usize == prefix.len() => return partial,
+            Ok(_) if out.is_empty() && inflate.total_in() as usize == consumed => return false,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+}
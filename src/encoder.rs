@@ -0,0 +1,250 @@
+//! A gzip encoder that can reset the compressor mid-stream.
+//!
+//! flate2's `GzEncoder` only offers sync flushes, which keep the deflate
+//! history, so this drives a raw deflate stream directly and writes the
+//! header and trailer itself.
+
+use std::io::{self, Write};
+use std::num::NonZeroU64;
+
+use flate2::{Compress, Compression, Crc, FlushCompress, Status};
+
+/// Window the `--rsyncable` rolling sum is taken over, as in GNU gzip. A
+/// power of two, so a mask finds the multiples of it.
+const RSYNC_WIN: usize = 4096;
+
+/// Base-two log of the deflate window, which flate2's `Compress` fixes.
+pub const WINDOW_BITS: u8 = 15;
+
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// RFC 1952 operating system byte for the host, with the values GNU gzip
+/// uses: NTFS on Windows, Unix on Unix-likes and "unknown" otherwise.
+fn host_os_byte() -> u8 {
+    if cfg!(windows) {
+        11
+    } else if cfg!(unix) {
+        3
+    } else {
+        255
+    }
+}
+
+/// Header fields written at the start of the member.
+pub struct Header<'a> {
+    pub name: Option<&'a str>,
+    /// Latin-1 text, without the terminating NUL.
+    pub comment: Option<&'a [u8]>,
+    pub mtime: u32,
+}
+
+impl Header<'_> {
+    /// Length of the header in bytes.
+    pub fn len(&self) -> usize {
+        10 + self.name.map_or(0, |name| name.len() + 1)
+            + self.comment.map_or(0, |comment| comment.len() + 1)
+    }
+
+    fn to_bytes(&self, level: Compression) -> Vec<u8> {
+        let mut flags = 0;
+        let mut fields = Vec::new();
+        if let Some(name) = self.name {
+            flags |= FNAME;
+            fields.extend_from_slice(name.as_bytes());
+            fields.push(0);
+        }
+        if let Some(comment) = self.comment {
+            flags |= FCOMMENT;
+            fields.extend_from_slice(comment);
+            fields.push(0);
+        }
+        let extra_flags = if level.level() >= Compression::best().level() {
+            2
+        } else if level.level() <= Compression::fast().level() {
+            4
+        } else {
+            0
+        };
+
+        let mut header = vec![0x1f, 0x8b, 8, flags];
+        header.extend_from_slice(&self.mtime.to_le_bytes());
+        header.extend_from_slice(&[extra_flags, host_os_byte()]);
+        header.extend_from_slice(&fields);
+        header
+    }
+}
+
+/// Rolling sum of the last `RSYNC_WIN` input bytes.
+struct Rsync {
+    window: Vec<u8>,
+    sum: u32,
+    seen: u64,
+    /// Bytes since the last flush point.
+    chunk: usize,
+}
+
+impl Rsync {
+    fn new() -> Self {
+        Rsync {
+            window: vec![0; RSYNC_WIN],
+            sum: 0,
+            seen: 0,
+            chunk: 0,
+        }
+    }
+
+    /// Offset just past the first flush point in `data`, if there is one.
+    ///
+    /// A point is where the sum is a multiple of the window size, as in GNU
+    /// gzip. Points closer than a window apart are skipped so that runs of a
+    /// single byte value don't flush on every byte.
+    fn boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &byte) in data.iter().enumerate() {
+            let slot = (self.seen % RSYNC_WIN as u64) as usize;
+            let full = self.seen >= RSYNC_WIN as u64;
+            if full {
+                self.sum -= u32::from(self.window[slot]);
+            }
+            self.sum += u32::from(byte);
+            self.window[slot] = byte;
+            self.seen += 1;
+            self.chunk += 1;
+            if full && self.chunk >= RSYNC_WIN && self.sum & (RSYNC_WIN as u32 - 1) == 0 {
+                self.chunk = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+/// Compresses everything written to it into a gzip member, or into a new
+/// member every block when a block size is set.
+pub struct Encoder<W: Write> {
+    inner: W,
+    header: Vec<u8>,
+    compress: Compress,
+    crc: Crc,
+    buf: Vec<u8>,
+    rsync: Option<Rsync>,
+    block_size: Option<NonZeroU64>,
+    /// Uncompressed bytes written to the current member.
+    block_len: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Write `header` to `inner` and start a member. With `rsyncable`, the
+    /// compressor is fully flushed at content-defined points so that a local
+    /// change to the input only changes the output near it.
+    pub fn new(
+        mut inner: W,
+        header: &Header,
+        level: Compression,
+        rsyncable: bool,
+    ) -> io::Result<Self> {
+        let header = header.to_bytes(level);
+        inner.write_all(&header)?;
+        Ok(Encoder {
+            inner,
+            header,
+            compress: Compress::new(level, false),
+            crc: Crc::new(),
+            buf: Vec::with_capacity(32 * 1024),
+            rsync: rsyncable.then(Rsync::new),
+            block_size: None,
+            block_len: 0,
+        })
+    }
+
+    /// End the member and start another, with the same header, after every
+    /// `size` uncompressed bytes. Each member can then be decompressed on
+    /// its own, at the cost of a header, trailer and fresh dictionary each.
+    pub fn block_size(mut self, size: Option<NonZeroU64>) -> Self {
+        self.block_size = size;
+        self
+    }
+
+    /// Compress `input` and write out whatever the compressor produces.
+    fn deflate(&mut self, mut input: &[u8], flush: FlushCompress) -> io::Result<()> {
+        loop {
+            self.buf.clear();
+            let before = self.compress.total_in();
+            let status = self.compress.compress_vec(input, &mut self.buf, flush)?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+            self.inner.write_all(&self.buf)?;
+
+            let done = match flush {
+                FlushCompress::Finish => status == Status::StreamEnd,
+                _ => input.is_empty() && self.buf.len() < self.buf.capacity(),
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Finish the deflate stream, write the CRC32/ISIZE trailer and return
+    /// the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_member()?;
+        Ok(self.inner)
+    }
+
+    fn finish_member(&mut self) -> io::Result<()> {
+        self.deflate(&[], FlushCompress::Finish)?;
+        self.inner.write_all(&self.crc.sum().to_le_bytes())?;
+        self.inner.write_all(&self.crc.amount().to_le_bytes())
+    }
+
+    /// Finish the current member and start the next one.
+    fn next_member(&mut self) -> io::Result<()> {
+        self.finish_member()?;
+        self.compress.reset();
+        self.crc.reset();
+        if self.rsync.is_some() {
+            self.rsync = Some(Rsync::new());
+        }
+        self.block_len = 0;
+        self.inner.write_all(&self.header)
+    }
+
+    /// Compress `data` into the current member.
+    fn write_member(&mut self, data: &[u8]) -> io::Result<()> {
+        self.crc.update(data);
+        self.block_len += data.len() as u64;
+        let mut rest = data;
+        while let Some(end) = self.rsync.as_mut().and_then(|rsync| rsync.boundary(rest)) {
+            self.deflate(&rest[..end], FlushCompress::Full)?;
+            rest = &rest[end..];
+        }
+        self.deflate(rest, FlushCompress::None)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(size) = self.block_size else {
+            self.write_member(data)?;
+            return Ok(data.len());
+        };
+        // A new member is only started once there is data for it, so that
+        // input of an exact multiple of the block size doesn't end with an
+        // empty member.
+        let mut rest = data;
+        while !rest.is_empty() {
+            if self.block_len == size.get() {
+                self.next_member()?;
+            }
+            let n = rest.len().min((size.get() - self.block_len) as usize);
+            self.write_member(&rest[..n])?;
+            rest = &rest[n..];
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate(&[], FlushCompress::Sync)?;
+        self.inner.flush()
+    }
+}
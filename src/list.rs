@@ -0,0 +1,389 @@
+//! Implementation of `--list`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flate2::bufread::GzDecoder;
+
+use crate::decoder::{take_prefix, GZIP_MAGIC};
+use crate::{error, fail, file_name, is_stdin, ratio, warning, Args, OnFile};
+
+/// Width of the size columns, as printed by GNU gzip with a 64-bit `off_t`.
+const WIDTH: usize = 19;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// Date and time columns of `-lv` when there is no time to show.
+const UNKNOWN_TIME: &str = "??? ?? ??:??";
+
+static MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Sizes and trailer fields of one compressed file.
+struct Listing {
+    compressed: u64,
+    /// Total uncompressed length of every member.
+    uncompressed: u64,
+    /// Header plus trailer length of every member, excluded from the ratio.
+    overhead: u64,
+    method: u8,
+    crc: u32,
+    mtime: u32,
+    /// The original file name stored in the header, without directories.
+    name: Option<String>,
+    comment: Option<String>,
+}
+
+/// The fields of an RFC 1952 header that `--list` uses.
+struct Header {
+    method: u8,
+    len: u64,
+    mtime: u32,
+    name: Option<String>,
+    comment: Option<String>,
+}
+
+impl Listing {
+    /// Read a complete gzip file. The header fields come from its first
+    /// member and the CRC from its last. The input is read as a stream, so
+    /// that standard input needn't be held in memory.
+    fn read<R: Read>(input: R) -> io::Result<Listing> {
+        let mut input = BufReader::new(Tally::new(input));
+        let mut start = Vec::new();
+        let header = read_header(&mut Record {
+            input: &mut input,
+            bytes: &mut start,
+        })?;
+        let (uncompressed, overhead) = read_members(Cursor::new(start).chain(&mut input))?;
+        // Anything after the last member counts toward the size too.
+        io::copy(&mut input, &mut io::sink())?;
+
+        let tally = input.into_inner();
+        let compressed = tally.count;
+        if compressed < header.len + 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of file",
+            ));
+        }
+        let crc = u32::from_le_bytes(tally.last[..4].try_into().unwrap());
+
+        Ok(Listing {
+            compressed,
+            uncompressed,
+            overhead,
+            method: header.method,
+            crc,
+            mtime: header.mtime,
+            name: header.name,
+            comment: header.comment,
+        })
+    }
+
+    /// The modification time stored in the header, if there is one.
+    fn header_time(&self) -> Option<SystemTime> {
+        (self.mtime != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(self.mtime as u64))
+    }
+}
+
+/// Passes `input` through, counting its bytes and keeping the last eight,
+/// which are the trailer of the last member unless something follows it.
+struct Tally<R> {
+    input: R,
+    count: u64,
+    last: [u8; 8],
+}
+
+impl<R> Tally<R> {
+    fn new(input: R) -> Self {
+        Tally {
+            input,
+            count: 0,
+            last: [0; 8],
+        }
+    }
+}
+
+impl<R: Read> Read for Tally<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        let keep = n.min(self.last.len());
+        self.last.rotate_left(keep);
+        self.last[8 - keep..].copy_from_slice(&buf[n - keep..n]);
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Passes `input` through, keeping a copy of what is read in `bytes`.
+struct Record<'a, R> {
+    input: &'a mut R,
+    bytes: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for Record<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Total uncompressed length and header plus trailer length of the members
+/// in `input`. The trailers' ISIZE fields can't be trusted for this: they
+/// only hold the length modulo 2^32, and only the last one is easy to find,
+/// so each member is decompressed and counted. Anything after the last
+/// member that isn't another member is ignored, as in GNU gzip.
+fn read_members<R: BufRead>(mut input: R) -> io::Result<(u64, u64)> {
+    let (mut uncompressed, mut overhead) = (0, 0);
+    // The magic number, method and flags, since `GzHeader` doesn't say
+    // whether there was a header CRC.
+    let mut start = take_prefix(&mut input, 4)?;
+    loop {
+        let hcrc = start.get(3).is_some_and(|flags| flags & FHCRC != 0);
+        let mut member = GzDecoder::new(Cursor::new(start).chain(input));
+        uncompressed += io::copy(&mut member, &mut io::sink())?;
+        overhead += member.header().map_or(0, crate::overhead) as u64;
+        if hcrc {
+            overhead += 2;
+        }
+        input = member.into_inner().into_inner().1;
+        start = take_prefix(&mut input, 4)?;
+        if !start.starts_with(&GZIP_MAGIC) {
+            return Ok((uncompressed, overhead));
+        }
+    }
+}
+
+/// Parse an RFC 1952 header, leaving `input` at the start of the
+/// compressed data.
+fn read_header<R: Read>(input: &mut R) -> io::Result<Header> {
+    let not_gzip = || io::Error::new(io::ErrorKind::InvalidData, "not in gzip format");
+
+    let mut fixed = [0; 10];
+    input.read_exact(&mut fixed).map_err(|_| not_gzip())?;
+    if fixed[..2] != [0x1f, 0x8b] {
+        return Err(not_gzip());
+    }
+    let (method, flags) = (fixed[2], fixed[3]);
+    let mtime = u32::from_le_bytes(fixed[4..8].try_into().unwrap());
+    let mut len = fixed.len() as u64;
+
+    if flags & FEXTRA != 0 {
+        let mut xlen = [0; 2];
+        input.read_exact(&mut xlen)?;
+        let xlen = u16::from_le_bytes(xlen) as u64;
+        io::copy(&mut input.take(xlen), &mut io::sink())?;
+        len += 2 + xlen;
+    }
+    let (mut name, mut comment) = (None, None);
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let mut field = Vec::new();
+            let mut byte = [0];
+            loop {
+                input.read_exact(&mut byte)?;
+                len += 1;
+                if byte[0] == 0 {
+                    break;
+                }
+                field.push(byte[0]);
+            }
+            if flag == FNAME {
+                name = Some(String::from_utf8_lossy(&field).into_owned());
+            } else {
+                // Comments are Latin-1, whose bytes are the first 256 chars.
+                comment = Some(field.iter().map(|&byte| char::from(byte)).collect());
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        input.read_exact(&mut [0; 2])?;
+        len += 2;
+    }
+
+    let name = name.and_then(|name| Some(Path::new(&name).file_name()?.to_str()?.to_owned()));
+    Ok(Header {
+        method,
+        len,
+        mtime,
+        name,
+        comment,
+    })
+}
+
+/// `time` in the local time zone, as the date and time columns of `-lv`.
+#[cfg(unix)]
+fn format_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as libc::time_t,
+        Err(e) => -(e.duration().as_secs() as libc::time_t),
+    };
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: localtime_r only writes to `tm`, which is read only if it
+    // reports success.
+    let tm = unsafe {
+        if libc::localtime_r(&secs, tm.as_mut_ptr()).is_null() {
+            return UNKNOWN_TIME.to_owned();
+        }
+        tm.assume_init()
+    };
+    format!(
+        "{}{:3} {:02}:{:02}",
+        MONTHS[tm.tm_mon as usize], tm.tm_mday, tm.tm_hour, tm.tm_min
+    )
+}
+
+#[cfg(not(unix))]
+fn format_time(_time: SystemTime) -> String {
+    UNKNOWN_TIME.to_owned()
+}
+
+/// Modification time of standard input, if it is a regular file.
+#[cfg(unix)]
+fn stdin_time() -> Option<SystemTime> {
+    use std::os::fd::AsFd;
+
+    let stdin = File::from(io::stdin().as_fd().try_clone_to_owned().ok()?);
+    let meta = stdin.metadata().ok()?;
+    meta.is_file().then(|| meta.modified().ok()).flatten()
+}
+
+#[cfg(not(unix))]
+fn stdin_time() -> Option<SystemTime> {
+    None
+}
+
+/// Print the `--list` table for the files named in `args`.
+pub fn list_files(args: &Args) {
+    let mut totals = (0, 0);
+    let mut last_overhead = 0;
+    let mut count = 0;
+    let mut out = io::stdout().lock();
+
+    // `name` and `file_time` are shown unless -N or -M ask for the ones stored
+    // in the header, as in GNU gzip.
+    let mut print = |listing: &Listing, name: PathBuf, file_time: Option<SystemTime>| {
+        if count == 0 {
+            if args.verbose() {
+                write!(out, "method  crc     date  time  ")?;
+            }
+            if !args.quiet {
+                writeln!(
+                    out,
+                    "{:>w$} {:>w$}  ratio uncompressed_name",
+                    "compressed",
+                    "uncompressed",
+                    w = WIDTH
+                )?;
+            }
+        }
+        let name = match &listing.name {
+            Some(stored) if args.restore_name() => name.with_file_name(stored),
+            _ => name,
+        };
+        if args.verbose() {
+            let method = if listing.method == 8 { "defla" } else { "" };
+            let time = match listing.header_time() {
+                Some(time) if args.restore_time() => Some(time),
+                _ => file_time,
+            };
+            let time = time.map_or_else(|| UNKNOWN_TIME.to_owned(), format_time);
+            write!(out, "{:5} {:08x} {} ", method, listing.crc, time)?;
+        }
+        writeln!(
+            out,
+            "{:>w$} {:>w$} {} {}",
+            listing.compressed,
+            listing.uncompressed,
+            ratio(
+                listing.uncompressed as i64 - (listing.compressed - listing.overhead) as i64,
+                listing.uncompressed as i64
+            ),
+            name.display(),
+            w = WIDTH
+        )?;
+        if let (true, Some(comment)) = (args.verbose(), &listing.comment) {
+            writeln!(out, "{:28}comment: {}", "", comment)?;
+        }
+        totals.0 += listing.compressed;
+        totals.1 += listing.uncompressed;
+        last_overhead = listing.overhead;
+        count += 1;
+        out.flush()
+    };
+
+    for file in args.inputs() {
+        if is_stdin(&file) {
+            match Listing::read(io::stdin().lock()) {
+                Ok(listing) => {
+                    if let Err(failure) =
+                        print(&listing, PathBuf::from("stdout"), stdin_time()).on(&file)
+                    {
+                        fail(failure);
+                    }
+                }
+                Err(e) => error(&file, &e),
+            }
+            continue;
+        }
+
+        let name = match file_name(&file).map(|name| args.decompressed_name(name)) {
+            Ok(Some(name)) => file.with_file_name(name),
+            // GNU gzip lists any file named on the command line, since -l
+            // writes nothing that needs a name, but skips them under -r.
+            Ok(None) if !args.recursive => file.clone(),
+            Ok(None) => {
+                if args.verbose() {
+                    warning(format_args!(
+                        "{}: unknown suffix -- ignored",
+                        file.display()
+                    ));
+                }
+                continue;
+            }
+            Err(e) => {
+                error(&file, &e);
+                continue;
+            }
+        };
+        let listing = File::open(&file).and_then(|input| {
+            let file_time = input.metadata()?.modified().ok();
+            Ok((Listing::read(input)?, file_time))
+        });
+        match listing {
+            Ok((listing, file_time)) => {
+                if let Err(failure) = print(&listing, name, file_time).on(&file) {
+                    fail(failure);
+                }
+            }
+            Err(e) => error(&file, &e),
+        }
+    }
+
+    if !args.quiet && count > 1 {
+        let indent = if args.verbose() { 28 } else { 0 };
+        let written = writeln!(
+            out,
+            "{:indent$}{:>w$} {:>w$} {} (totals)",
+            "",
+            totals.0,
+            totals.1,
+            ratio(
+                totals.1 as i64 - (totals.0 - last_overhead) as i64,
+                totals.1 as i64
+            ),
+            indent = indent,
+            w = WIDTH
+        );
+        if let Err(failure) = written.and_then(|_| out.flush()).on(Path::new("stdout")) {
+            fail(failure);
+        }
+    }
+}
@@ -1,12 +1,49 @@
-use std::io::Write;
-use std::{fs::OpenOptions, io, path::PathBuf, time::SystemTime};
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, SystemTime};
+use std::{fmt, process, thread};
 
 use clap::Parser;
-use flate2::{read::GzDecoder, Compression, GzBuilder};
+use flate2::{Compression, GzHeader};
+
+use ascii::{Ascii, LOCAL_EOL};
+use decoder::{Decoder, Trailing};
+use encoder::{Encoder, Header, WINDOW_BITS};
+
+mod ascii;
+mod decoder;
+mod encoder;
+mod list;
+mod parallel;
 
 const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
 
-static LEVEL_FLAGS: &'static [&'static str] = &[
+/// Block size `--processes` compresses in without `--blocks`, as in pigz.
+const DEFAULT_BLOCK_SIZE: u64 = 128 * 1024;
+
+/// Deepest directory nesting `--recursive` descends into.
+const MAX_DEPTH: usize = 256;
+
+/// Most threads `--processes` may ask for.
+const MAX_PROCESSES: usize = 256;
+
+/// Longest `--suffix` accepted, as in GNU gzip.
+const MAX_SUFFIX: usize = 30;
+
+/// Permissions of a file made from another before `copy_metadata` gives it
+/// the input's, so no one else can read it in the meantime.
+const PRIVATE_MODE: u32 = 0o600;
+
+/// Permissions of a file made from standard input, as for any new file.
+const NEW_FILE_MODE: u32 = 0o666;
+
+/// Suffixes recognized on compressed files, in addition to `--suffix`.
+static KNOWN_SUFFIXES: &[&str] = &[".gz", ".z", ".taz", ".tgz", "-gz", "-z", "_z"];
+
+static LEVEL_FLAGS: &[&str] = &[
     "level_1", "level_2", "level_3", "level_4", "level_5", "level_6", "level_7", "level_8",
     "level_9",
 ];
@@ -31,6 +68,15 @@ struct Args {
     #[clap(short = 'c', long = "stdout")]
     to_stdout: bool,
 
+    /// Write the result of a single input to FILE
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["to-stdout", "recursive"]
+    )]
+    output: Option<PathBuf>,
+
     /// Decompress
     #[clap(short, long)]
     decompress: bool,
@@ -44,35 +90,48 @@ struct Args {
     keep: bool,
 
     /// Save or restore the original name and timestamp
-    #[clap(short = 'N', long)]
+    #[clap(short = 'N', long, overrides_with = "no-name")]
     name: bool,
 
     /// Don't save or restore the original file name and timestamp
-    #[clap(short, long)]
+    #[clap(short, long, overrides_with = "name")]
     no_name: bool,
 
     /// Don't save or restore the original file time
-    #[clap(short = 'm')]
+    #[clap(short = 'm', overrides_with = "time")]
     no_time: bool,
 
     /// Save or restore the original modification time
-    #[clap(short = 'M', long, hide = true)]
+    #[clap(short = 'M', long, hide = true, overrides_with = "no-time")]
     time: bool,
 
+    /// Prompt as if stdin were a terminal, for testing
+    #[clap(long, hide = true)]
+    presume_input_tty: bool,
+
     /// Recurse through directories
     #[clap(short, long)]
     recursive: bool,
 
+    /// Skip files and directories named GLOB when recursing
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
     /// List the file contents
     #[clap(short, long)]
     list: bool,
 
-    /// Be verbose
-    #[clap(short, long)]
-    verbose: bool,
+    /// Be verbose; twice to also show compression settings
+    #[clap(
+        short = 'v',
+        long = "verbose",
+        parse(from_occurrences),
+        overrides_with = "quiet"
+    )]
+    verbosity: u64,
 
     /// Be very quiet
-    #[clap(short, long)]
+    #[clap(short, long, overrides_with = "verbosity")]
     quiet: bool,
 
     /// Test .gz file integrity
@@ -154,13 +213,25 @@ struct Args {
     level_9: bool,
 
     /// Use suffix SUF on compressed files
-    #[clap(short = 'S', long, default_value = "", value_name = "SUF")]
+    #[clap(short = 'S', long, default_value = ".gz", value_name = "SUF")]
     suffix: String,
 
     /// Make rsync-friendly archive
     #[clap(long)]
     rsyncable: bool,
 
+    /// Start a new member every N input bytes
+    #[clap(long, value_name = "N")]
+    blocks: Option<NonZeroU64>,
+
+    /// Store TEXT as a comment in the compressed file's header
+    #[clap(long, value_name = "TEXT")]
+    comment: Option<String>,
+
+    /// Compress blocks on N threads, or all cores if 0
+    #[clap(short = 'p', long, value_name = "N")]
+    processes: Option<usize>,
+
     /// Synchronous output (safer if system crashes, but slower)
     #[clap(long)]
     synchronous: bool,
@@ -170,6 +241,22 @@ struct Args {
 }
 
 impl Args {
+    /// Imply `-d` or `-dc` when run as `gunzip` or `zcat`, so that the binary
+    /// can be installed under those names as in GNU gzip.
+    fn apply_program_name(&mut self, program: &Path) {
+        let name = program
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let name = name.strip_suffix(".exe").unwrap_or(name);
+        if name.starts_with("un") || name.starts_with("gun") {
+            self.decompress = true;
+        } else if name.get(1..) == Some("cat") || name == "gzcat" {
+            self.decompress = true;
+            self.to_stdout = true;
+        }
+    }
+
     /// User-identified compression level for this run of gzip.
     fn compression_level(&self) -> u32 {
         match self {
@@ -186,93 +273,1027 @@ impl Args {
         }
     }
 
-    /// Whether to use stdin for input data.
-    fn is_stdin(&self) -> bool {
-        self.files.len() == 0 || self.files[0].to_str().unwrap() == "-"
+    /// Compressed-file suffixes to try, in order of preference.
+    ///
+    /// The user's `--suffix` normally goes first, but if it is itself the tail
+    /// of a built-in suffix (e.g. `z` of `.gz`) it goes last so that the
+    /// longer match wins.
+    fn known_suffixes(&self) -> Vec<String> {
+        let mut suffixes: Vec<String> = KNOWN_SUFFIXES.iter().map(|s| s.to_string()).collect();
+        let suffix = self.suffix.to_ascii_lowercase();
+        if !KNOWN_SUFFIXES.contains(&suffix.as_str()) {
+            if KNOWN_SUFFIXES
+                .iter()
+                .any(|known| known.len() > suffix.len() && known.ends_with(&suffix))
+            {
+                suffixes.push(suffix);
+            } else {
+                suffixes.insert(0, suffix);
+            }
+        }
+        suffixes
+    }
+
+    /// The recognized compression suffix `name` ends in, as written in
+    /// `name`.
+    fn suffix_of<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let lower = name.to_ascii_lowercase();
+        self.known_suffixes()
+            .iter()
+            .find(|suffix| lower.len() > suffix.len() && lower.ends_with(suffix.as_str()))
+            .map(|suffix| &name[name.len() - suffix.len()..])
+    }
+
+    /// Name of the decompressed output for the compressed file `name`, or
+    /// `None` if `name` doesn't end in a recognized suffix.
+    fn decompressed_name(&self, name: &str) -> Option<String> {
+        let suffix = self.suffix_of(name)?;
+        let stem = &name[..name.len() - suffix.len()];
+        Some(match suffix.to_ascii_lowercase().as_str() {
+            ".tgz" | ".taz" => format!("{}.tar", stem),
+            _ => stem.to_owned(),
+        })
+    }
+
+    /// Whether `-v` was given.
+    fn verbose(&self) -> bool {
+        self.verbosity > 0
+    }
+
+    /// Input bytes per gzip member, if compression splits the input. Threads
+    /// need blocks to work on, so `--processes` implies a default size.
+    fn block_size(&self) -> Option<NonZeroU64> {
+        self.blocks
+            .or_else(|| self.processes.and(NonZeroU64::new(DEFAULT_BLOCK_SIZE)))
+    }
+
+    /// Number of threads to compress blocks on.
+    fn threads(&self) -> NonZeroUsize {
+        let cores = || thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        match self.processes {
+            Some(n) => NonZeroUsize::new(n).unwrap_or_else(cores),
+            None => cores(),
+        }
+    }
+
+    /// Number of gzip members compressing `len` bytes produces.
+    fn member_count(&self, len: u64) -> u64 {
+        self.block_size()
+            .map_or(1, |size| len.div_ceil(size.get()).max(1))
+    }
+
+    /// Whether compression should store the input's file name.
+    fn save_name(&self) -> bool {
+        !self.no_name
+    }
+
+    /// Whether compression should store the input's modification time.
+    fn save_time(&self) -> bool {
+        (!self.no_name || self.time) && !self.no_time
+    }
+
+    /// Whether decompression should name its output after the file name
+    /// stored in the gzip header.
+    fn restore_name(&self) -> bool {
+        self.name && !self.no_name
+    }
+
+    /// Whether decompression should give its output the modification time
+    /// stored in the gzip header.
+    fn restore_time(&self) -> bool {
+        (self.name || self.time) && !self.no_name && !self.no_time
+    }
+
+    /// Files to operate on, with standard input standing in for an empty
+    /// list. Directories are expanded with `--recursive` and otherwise
+    /// skipped with a warning.
+    fn inputs(&self) -> Vec<PathBuf> {
+        if self.files.is_empty() {
+            return vec![PathBuf::from("-")];
+        }
+
+        let mut inputs = Vec::new();
+        for file in &self.files {
+            if is_stdin(file) {
+                inputs.push(file.clone());
+            } else if self.is_unfollowed_link(file) {
+                continue;
+            } else if file.is_dir() {
+                if self.recursive {
+                    self.walk(file, &mut Vec::new(), &mut inputs);
+                } else {
+                    warning(format_args!("{} is a directory -- ignored", file.display()));
+                }
+            } else {
+                inputs.push(file.clone());
+            }
+        }
+        inputs
+    }
+
+    /// Append the files under `dir` that this run should process to `inputs`,
+    /// silently skipping those that are already (de)compressed or excluded.
+    /// `ancestors` are the directories being walked that `dir` is under.
+    fn walk(&self, dir: &Path, ancestors: &mut Vec<PathBuf>, inputs: &mut Vec<PathBuf>) {
+        // Symbolic links followed under --force can lead back to a directory
+        // that is already being walked.
+        let real = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+        if ancestors.contains(&real) {
+            warning(format_args!("{}: directory loop -- skipped", dir.display()));
+            return;
+        }
+        if ancestors.len() == MAX_DEPTH {
+            error(dir, &io::Error::other("directory nesting too deep"));
+            return;
+        }
+
+        let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(e) => {
+                error(dir, &e);
+                return;
+            }
+        };
+        entries.sort();
+
+        ancestors.push(real);
+        for path in entries {
+            if self.is_excluded(&path) || self.is_unfollowed_link(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                self.walk(&path, ancestors, inputs);
+            } else if path.is_file() {
+                let compressed =
+                    file_name(&path).is_ok_and(|name| self.decompressed_name(name).is_some());
+                let decompressing = self.decompress || self.test || self.list;
+                if compressed == decompressing || (!decompressing && self.force) {
+                    inputs.push(path);
+                }
+            }
+        }
+        ancestors.pop();
+    }
+
+    /// Whether `--exclude` skips `path` when recursing.
+    fn is_excluded(&self, path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map_or(&[][..], |name| name.as_encoded_bytes());
+        self.exclude
+            .iter()
+            .any(|pattern| glob_matches(pattern.as_bytes(), name))
+    }
+
+    /// Whether `file` is a symbolic link that must not be followed, which
+    /// is the case unless `--force` or `--stdout` is given, or `--list` or
+    /// `--test`, which write no files either.
+    fn is_unfollowed_link(&self, file: &Path) -> bool {
+        let is_link = fs::symlink_metadata(file)
+            .map(|meta| meta.file_type().is_symlink())
+            .unwrap_or(false);
+        let follow = self.force || self.to_stdout || self.list || self.test;
+        if is_link && !follow {
+            let e = io::Error::other("Too many levels of symbolic links");
+            error(file, &e);
+            return true;
+        }
+        false
     }
 }
 
+/// Whether `file` names standard input.
+fn is_stdin(file: &Path) -> bool {
+    file == Path::new("-")
+}
+
+/// Name to use for `file` in messages.
+fn display_name(file: &Path) -> String {
+    if is_stdin(file) {
+        "stdin".to_owned()
+    } else {
+        file.display().to_string()
+    }
+}
+
+/// Exit statuses, as in GNU gzip.
+const OK: i32 = 0;
+const ERROR: i32 = 1;
+const WARNING: i32 = 2;
+
+/// Status to exit with once every file has been handled.
+static EXIT_CODE: AtomicI32 = AtomicI32::new(OK);
+
+/// Whether warnings are silenced by `--quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
 fn main() {
-    let args = Args::parse();
+    // GNU gzip spells this test-only option with three dashes, which clap
+    // can't express.
+    let mut args = Args::parse_from(std::env::args_os().map(|arg| {
+        if arg == "---presume-input-tty" {
+            "--presume-input-tty".into()
+        } else {
+            arg
+        }
+    }));
+    if let Some(program) = std::env::args_os().next() {
+        args.apply_program_name(Path::new(&program));
+    }
+    // An empty suffix would make the output name the same as the input's.
+    if args.suffix.is_empty() || args.suffix.len() > MAX_SUFFIX {
+        eprintln!("gzip: invalid suffix '{}'", args.suffix);
+        process::exit(ERROR);
+    }
+    if args.processes.is_some_and(|n| n > MAX_PROCESSES) {
+        eprintln!("gzip: --processes must be at most {}", MAX_PROCESSES);
+        process::exit(ERROR);
+    }
+    if args.output.is_some() && args.files.len() > 1 {
+        eprintln!("gzip: --output needs a single input file");
+        process::exit(ERROR);
+    }
+    if let (Some(output), [input]) = (&args.output, args.files.as_slice()) {
+        let output = fs::canonicalize(output).ok();
+        if output.is_some() && output == fs::canonicalize(input).ok() {
+            eprintln!(
+                "gzip: {}: input and output are the same file",
+                input.display()
+            );
+            process::exit(ERROR);
+        }
+    }
+    if args
+        .comment
+        .as_deref()
+        .is_some_and(|text| latin1(text).is_none())
+    {
+        eprintln!("gzip: comment must be Latin-1 text without NUL characters");
+        process::exit(ERROR);
+    }
+    QUIET.store(args.quiet, Ordering::Relaxed);
 
-    if args.decompress {
-        decompress_files(args)
+    if args.list {
+        list::list_files(&args)
+    } else if args.test {
+        test_files(&args)
+    } else if args.decompress {
+        decompress_files(&args)
     } else {
-        compress_files(args)
+        compress_files(&args)
     }
+
+    process::exit(EXIT_CODE.load(Ordering::Relaxed));
 }
 
-fn get_output_file(file_name: Option<String>) -> Box<dyn Write> {
-    if let Some(f) = file_name {
-        Box::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&f)
-                .unwrap(),
-        )
+/// Report `e`, which happened while working on `file`, and make the run
+/// exit with an error status.
+fn error(file: &Path, e: &io::Error) {
+    eprintln!("gzip: {}: {}", display_name(file), describe(e));
+    EXIT_CODE.store(ERROR, Ordering::Relaxed);
+}
+
+/// Print a warning unless `--quiet` is given, and make the run exit with a
+/// warning status unless an error occurs too.
+fn warning(msg: fmt::Arguments) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("gzip: {}", msg);
+    }
+    let _ = EXIT_CODE.compare_exchange(OK, WARNING, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Report `failure`. A broken pipe means whoever reads our output has gone
+/// away, as in `gzip -dc big.gz | head`, so stop quietly with the status so
+/// far rather than complain about every remaining file.
+fn fail(failure: Failure) {
+    if failure.error.kind() == io::ErrorKind::BrokenPipe {
+        process::exit(EXIT_CODE.load(Ordering::Relaxed));
+    }
+    error(&failure.file, &failure.error);
+}
+
+/// An I/O error and the file it happened on.
+struct Failure {
+    file: PathBuf,
+    error: io::Error,
+}
+
+trait OnFile<T> {
+    /// Attach `file` to an I/O error.
+    fn on(self, file: &Path) -> Result<T, Failure>;
+}
+
+impl<T> OnFile<T> for io::Result<T> {
+    fn on(self, file: &Path) -> Result<T, Failure> {
+        self.map_err(|error| Failure {
+            file: file.to_owned(),
+            error,
+        })
+    }
+}
+
+/// `text` encoded as Latin-1, which RFC 1952 header comments are written
+/// in, or `None` if it has characters Latin-1 lacks or a NUL.
+fn latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars()
+        .map(|c| u8::try_from(c).ok().filter(|&byte| byte != 0))
+        .collect()
+}
+
+/// Whether `name` matches the shell wildcard `pattern`, in which `*` matches
+/// any run of bytes, `?` any one byte and `[...]` one byte from a set.
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        [b'*', rest @ ..] => (0..=name.len()).any(|i| glob_matches(rest, &name[i..])),
+        [b'?', rest @ ..] => !name.is_empty() && glob_matches(rest, &name[1..]),
+        [b'[', class @ ..] => match (class.iter().skip(1).position(|&b| b == b']'), name) {
+            (Some(end), [byte, name @ ..]) => {
+                let (set, rest) = class.split_at(end + 1);
+                let (negated, set) = match set {
+                    [b'!' | b'^', set @ ..] => (true, set),
+                    _ => (false, set),
+                };
+                let mut found = false;
+                let mut i = 0;
+                while i < set.len() {
+                    if i + 2 < set.len() && set[i + 1] == b'-' {
+                        found |= (set[i]..=set[i + 2]).contains(byte);
+                        i += 3;
+                    } else {
+                        found |= set[i] == *byte;
+                        i += 1;
+                    }
+                }
+                found != negated && glob_matches(&rest[1..], name)
+            }
+            // An unclosed bracket matches itself.
+            (None, [b'[', name @ ..]) => glob_matches(class, name),
+            _ => false,
+        },
+        [byte, rest @ ..] => name.first() == Some(byte) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/// The last component of `file`, which must be UTF-8 for suffix handling.
+fn file_name(file: &Path) -> io::Result<&str> {
+    file.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))
+}
+
+/// Describe `e` the way GNU gzip would, mapping flate2's messages onto
+/// gzip's and dropping Rust's "(os error N)" suffix.
+fn describe(e: &io::Error) -> String {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return "unexpected end of file".to_owned();
+    }
+    let msg = e.to_string();
+    if e.raw_os_error().is_some() {
+        if let Some((msg, _)) = msg.rsplit_once(" (os error") {
+            return msg.to_owned();
+        }
+    }
+    match msg.as_str() {
+        "invalid gzip header" => "not in gzip format",
+        "corrupt deflate stream" => "invalid compressed data--format violated",
+        "corrupt gzip stream does not have a matching checksum" => {
+            "invalid compressed data--crc error"
+        }
+        _ => &msg,
+    }
+    .to_owned()
+}
+
+/// Create `path` for output with permissions `mode` (less the umask), or use
+/// stdout if it's `None`. A device or FIFO named by `--output` is written in
+/// place instead. Returns `None` if `path` already exists and mustn't be
+/// overwritten.
+fn open_output(
+    args: &Args,
+    path: &Option<PathBuf>,
+    mode: u32,
+) -> Result<Option<Box<dyn Write>>, Failure> {
+    match path {
+        Some(path) if args.output.as_ref() == Some(path) && is_special(path) => {
+            let file = OpenOptions::new().write(true).open(path).on(path)?;
+            Ok(Some(Box::new(file)))
+        }
+        Some(path) => {
+            if fs::symlink_metadata(path).is_ok() {
+                if !args.force && !confirm_overwrite(args, path) {
+                    return Ok(None);
+                }
+                fs::remove_file(path).on(path)?;
+            }
+            let mut options = OpenOptions::new();
+            // A new file only: anything that took the old one's place since
+            // is not ours to write into.
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+            #[cfg(not(unix))]
+            let _ = mode;
+            let file = options.open(path).on(path)?;
+            Ok(Some(Box::new(file)))
+        }
+        None => Ok(Some(Box::new(io::stdout()))),
+    }
+}
+
+/// Whether `path` exists but isn't a regular file, like `/dev/null`. Such an
+/// output is only written to, never removed or given the input's metadata.
+fn is_special(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| !meta.is_file())
+}
+
+/// Ask on the terminal whether to overwrite `path`. Without a terminal to
+/// ask on, or with `--quiet`, the answer is no.
+fn confirm_overwrite(args: &Args, path: &Path) -> bool {
+    if !args.quiet {
+        eprint!("gzip: {} already exists;", path.display());
+        let mut answer = String::new();
+        if args.presume_input_tty || io::stdin().is_terminal() {
+            eprint!(" do you wish to overwrite (y or n)? ");
+            // An unreadable answer counts as no.
+            let _ = io::stdin().lock().read_line(&mut answer);
+        }
+        if answer.starts_with(['y', 'Y']) {
+            return true;
+        }
+        eprintln!("\tnot overwritten");
+    }
+    let _ = EXIT_CODE.compare_exchange(OK, WARNING, Ordering::Relaxed, Ordering::Relaxed);
+    false
+}
+
+/// Exit rather than write compressed data to a terminal on stdout, or, when
+/// `decompress`ing, read it from one on stdin, unless `--force` is given.
+/// Such data is of no use on a terminal, and a user who runs gzip without
+/// arguments there more likely wants help, as in GNU gzip.
+fn refuse_terminal(args: &Args, decompress: bool) {
+    let tty = if decompress {
+        io::stdin().is_terminal()
     } else {
-        Box::new(io::stdout())
+        io::stdout().is_terminal()
+    };
+    if args.force || !(tty || args.presume_input_tty) {
+        return;
     }
+    if !args.quiet {
+        let (direction, prefix) = if decompress {
+            ("read from", "de")
+        } else {
+            ("written to", "")
+        };
+        eprintln!(
+            "gzip: compressed data not {} a terminal. Use -f to force {}compression.",
+            direction, prefix
+        );
+        eprintln!("For help, type: gzip -h");
+    }
+    process::exit(ERROR);
 }
 
-fn decompress_files(args: Args) {
-    if args.is_stdin() {
-        let mut gz_in = GzDecoder::new(io::stdin());
-        io::copy(&mut gz_in, &mut io::stdout()).unwrap();
+/// The gzip MTIME for `time`, or 0 (no timestamp) with a warning if it
+/// doesn't fit in the header's 32 bits.
+fn gzip_mtime(file: &Path, time: SystemTime) -> u32 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) if since.as_secs() <= u32::MAX as u64 => since.as_secs() as u32,
+        _ => {
+            warning(format_args!(
+                "{}: warning: file timestamp out of range for gzip format",
+                file.display()
+            ));
+            0
+        }
+    }
+}
+
+/// Format `num / den` as a percentage, like GNU gzip's `display_ratio`.
+fn ratio(num: i64, den: i64) -> String {
+    let ratio = if den == 0 {
+        0.0
     } else {
-        for file in args.files {
-            let file_name = file.file_name().unwrap().to_str().unwrap();
-            let output_file = if args.to_stdout {
-                None
-            } else {
-                Some(
-                    file_name
-                        .strip_suffix(".gz")
-                        .unwrap_or(file_name)
-                        .to_owned(),
-                )
-            };
-            let mut output = get_output_file(output_file);
-            let mut gz_in = GzDecoder::new(OpenOptions::new().read(true).open(file).unwrap());
-            io::copy(&mut gz_in, &mut output).unwrap();
+        100.0 * num as f64 / den as f64
+    };
+    format!("{:5.1}%", ratio)
+}
+
+/// Size of a gzip member's header and trailer, which `-v` leaves out of the
+/// compression ratio.
+fn overhead(header: &GzHeader) -> i64 {
+    let mut len = 10 + 8;
+    if let Some(extra) = header.extra() {
+        len += 2 + extra.len();
+    }
+    if let Some(name) = header.filename() {
+        len += name.len() + 1;
+    }
+    if let Some(comment) = header.comment() {
+        len += comment.len() + 1;
+    }
+    len as i64
+}
+
+/// The original file name stored in `header`, stripped of any directory
+/// components so that it can't name a file outside the input's directory.
+fn stored_name(header: &GzHeader) -> Option<String> {
+    let name = String::from_utf8_lossy(header.filename()?).into_owned();
+    let name = Path::new(&name).file_name()?.to_str()?;
+    Some(name.to_owned())
+}
+
+/// Print the `-v` summary line for a file that was (de)compressed into
+/// `output`, or into stdout if that's `None`.
+fn report(args: &Args, file: &Path, ratio: &str, output: &Option<PathBuf>) {
+    let output = match output {
+        Some(output) => output.display().to_string(),
+        None => "stdout".to_owned(),
+    };
+    let action = if args.keep {
+        "created"
+    } else {
+        "replaced with"
+    };
+    eprintln!("{}:\t{} -- {} {}", file.display(), ratio, action, output);
+}
+
+/// A writer that counts the bytes passing through it.
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> Counter<W> {
+    fn new(inner: W) -> Self {
+        Counter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copy `input` to `output`, converting line endings to `eol` with `--ascii`.
+fn copy_data<R: Read, W: Write + ?Sized>(
+    args: &Args,
+    mut input: R,
+    output: &mut W,
+    eol: &'static [u8],
+) -> io::Result<u64> {
+    if args.ascii {
+        io::copy(&mut Ascii::new(input, eol), output)
+    } else {
+        io::copy(&mut input, output)
+    }
+}
+
+/// Decompress `input` into a sink, failing if it is corrupt or truncated.
+fn test_input<R: Read>(args: &Args, file: &Path, input: R) -> io::Result<()> {
+    let mut gz_in = Decoder::new(BufReader::new(input), false, false)?;
+    io::copy(&mut gz_in, &mut io::sink())?;
+    warn_trailing(args, file, &gz_in);
+    Ok(())
+}
+
+/// Warn about data after the last member of `file`, as GNU gzip does.
+/// Trailing zeros, which some tape and block devices pad with, are only
+/// mentioned under `-v`.
+fn warn_trailing<R: BufRead>(args: &Args, file: &Path, gz_in: &Decoder<R>) {
+    let file = display_name(file);
+    match gz_in.trailing() {
+        Trailing::Garbage => warning(format_args!(
+            "{}: decompression OK, trailing garbage ignored",
+            file
+        )),
+        Trailing::Zeros if args.verbose() => warning(format_args!(
+            "{}: decompression OK, trailing zero bytes ignored",
+            file
+        )),
+        _ => {}
+    }
+}
+
+/// Check the integrity of each input, printing nothing on success unless
+/// `-v` is given.
+fn test_files(args: &Args) {
+    for file in args.inputs() {
+        let result = if is_stdin(&file) {
+            refuse_terminal(args, true);
+            test_input(args, &file, io::stdin())
+        } else {
+            File::open(&file).and_then(|input| test_input(args, &file, input))
+        };
+        match result {
+            Ok(()) if args.verbose() => eprintln!("{}:\t OK", display_name(&file)),
+            Ok(()) => {}
+            Err(e) => error(&file, &e),
         }
     }
 }
 
-fn compress_files(args: Args) {
-    let compression_level = args.compression_level();
+/// Remove the partly written output, if there is one, after `failure`.
+fn discard_output(output_file: &Option<PathBuf>, failure: Failure) -> Failure {
+    if let Some(path) = output_file.as_deref().filter(|path| !is_special(path)) {
+        let _ = fs::remove_file(path);
+    }
+    failure
+}
+
+/// Give the output `path` the permissions, owner and times of the input
+/// described by `meta`, with `mtime` replacing its modification time. A
+/// failure is only a warning.
+fn copy_metadata(meta: &fs::Metadata, path: &Path, mtime: Option<SystemTime>) {
+    if is_special(path) {
+        return;
+    }
+    if let Err(e) = try_copy_metadata(meta, path, mtime) {
+        warning(format_args!("{}: {}", path.display(), describe(&e)));
+    }
+}
+
+fn try_copy_metadata(
+    meta: &fs::Metadata,
+    path: &Path,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    let output = OpenOptions::new().write(true).open(path)?;
+    let mtime = match mtime {
+        Some(mtime) => mtime,
+        None => meta.modified()?,
+    };
+    output.set_times(
+        FileTimes::new()
+            .set_accessed(meta.accessed()?)
+            .set_modified(mtime),
+    )?;
 
-    if args.is_stdin() {
-        let gz_writer = GzBuilder::new();
-        let mut writer = gz_writer.write(io::stdout(), Compression::new(compression_level));
-        io::copy(&mut io::stdin(), &mut writer).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+
+        // As in GNU gzip: the group first, then the permissions, then the
+        // owner, so the file never belongs to someone else with permissions
+        // meant for us. Ownership is best effort, since usually only root
+        // may give files away.
+        let _ = fchown(&output, None, Some(meta.gid()));
+        output.set_permissions(fs::Permissions::from_mode(meta.mode() & 0o777))?;
+        let _ = fchown(&output, Some(meta.uid()), None);
+    }
+    #[cfg(not(unix))]
+    output.set_permissions(meta.permissions())?;
+    Ok(())
+}
+
+/// With `--synchronous`, make sure the output and its directory entry are on
+/// disk before the input can be removed.
+fn sync_output(args: &Args, output_file: &Option<PathBuf>) -> Result<(), Failure> {
+    let path = match output_file {
+        Some(path) if args.synchronous && !is_special(path) => path,
+        _ => return Ok(()),
+    };
+    File::open(path).and_then(|f| f.sync_all()).on(path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).and_then(|f| f.sync_all()).on(dir)
+}
+
+/// Whether to leave `file`, with metadata `meta`, alone rather than replace
+/// it, warning why. As in GNU gzip, only regular files are replaced, never
+/// set-user-ID or set-group-ID ones, and without `--force` not ones with the
+/// sticky bit or other links either. With `--stdout` nothing is replaced.
+fn refuse_input(args: &Args, file: &Path, meta: &fs::Metadata) -> bool {
+    if args.to_stdout {
+        return false;
+    }
+    let reason = if !meta.is_file() {
+        Some("is not a directory or a regular file - ignored".to_owned())
     } else {
-        for file in args.files {
-            let file_name = file.file_name().unwrap().to_str().unwrap();
-            let gz_writer = GzBuilder::new().filename(file_name);
-            let gz_out_file = if args.to_stdout {
-                None
-            } else {
-                Some(format!("{}.gz", file_name))
-            };
-            let gz_out = get_output_file(gz_out_file);
-            let meta = file.metadata().expect("failed to acquire file metadata");
-            let gz_writer = gz_writer.mtime(
-                meta.modified()
-                    .unwrap()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32,
-            );
+        special_file(args, meta)
+    };
+    if let Some(reason) = &reason {
+        warning(format_args!("{} {}", file.display(), reason));
+    }
+    reason.is_some()
+}
+
+/// Why a regular file with metadata `meta` mustn't be replaced, if it
+/// mustn't.
+#[cfg(unix)]
+fn special_file(args: &Args, meta: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    const SET_UID: u32 = 0o4000;
+    const SET_GID: u32 = 0o2000;
+    const STICKY: u32 = 0o1000;
+    if meta.mode() & SET_UID != 0 {
+        Some("is set-user-ID on execution - ignored".to_owned())
+    } else if meta.mode() & SET_GID != 0 {
+        Some("is set-group-ID on execution - ignored".to_owned())
+    } else if args.force {
+        None
+    } else if meta.mode() & STICKY != 0 {
+        Some("has the sticky bit set - file ignored".to_owned())
+    } else if meta.nlink() > 1 {
+        let others = meta.nlink() - 1;
+        let plural = if others == 1 { "" } else { "s" };
+        Some(format!(
+            "has {} other link{} -- file ignored",
+            others, plural
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file(_args: &Args, _meta: &fs::Metadata) -> Option<String> {
+    None
+}
+
+/// Remove `file` once its output has been completely written, unless the
+/// user asked to keep it or the output went to stdout.
+fn remove_input(args: &Args, file: &Path) {
+    if !args.keep && !args.to_stdout {
+        if let Err(e) = fs::remove_file(file) {
+            warning(format_args!("{}: {}", file.display(), describe(&e)));
+        }
+    }
+}
+
+fn decompress_files(args: &Args) {
+    // Not a warning for the exit status: the option is harmless here.
+    if args.processes.is_some() && args.verbose() {
+        eprintln!("gzip: --processes only applies to compression -- ignored");
+    }
+    for file in args.inputs() {
+        let result = if is_stdin(&file) {
+            decompress_stdin(args)
+        } else {
+            decompress_file(args, &file)
+        };
+        if let Err(failure) = result {
+            fail(failure);
+        }
+    }
+}
+
+fn decompress_stdin(args: &Args) -> Result<(), Failure> {
+    refuse_terminal(args, true);
+    let stdin = Path::new("-");
+    // Standard input goes to standard output unless --output names a file.
+    let mut gz_in = Decoder::new(io::stdin().lock(), args.force, true).on(stdin)?;
+    let Some(mut output) = open_output(args, &args.output, NEW_FILE_MODE)? else {
+        return Ok(());
+    };
+    copy_data(args, &mut gz_in, &mut output, LOCAL_EOL)
+        .and_then(|_| output.flush())
+        .on(stdin)
+        .map_err(|failure| discard_output(&args.output, failure))?;
+    drop(output);
+    warn_trailing(args, stdin, &gz_in);
+    sync_output(args, &args.output)
+}
+
+fn decompress_file(args: &Args, file: &Path) -> Result<(), Failure> {
+    let mut output_file = if args.to_stdout {
+        None
+    } else if args.output.is_some() {
+        args.output.clone()
+    } else {
+        match args.decompressed_name(file_name(file).on(file)?) {
+            // Write next to the source, not into the working directory.
+            Some(name) => Some(file.with_file_name(name)),
+            None => {
+                warning(format_args!(
+                    "{}: unknown suffix -- ignored",
+                    file.display()
+                ));
+                return Ok(());
+            }
+        }
+    };
+    // Checked before opening, which would wait for a writer on a FIFO.
+    if refuse_input(args, file, &fs::metadata(file).on(file)?) {
+        return Ok(());
+    }
+    let input = File::open(file).on(file)?;
+    let meta = input.metadata().on(file)?;
+    let bytes_in = meta.len() as i64;
+    let mut gz_in = Decoder::new(BufReader::new(input), args.force, args.to_stdout).on(file)?;
+
+    // The header is only parsed once decompression starts, so read the
+    // first chunk before choosing the output name.
+    let mut first = vec![0; 32 * 1024];
+    let n = gz_in.read(&mut first).on(file)?;
+    first.truncate(n);
+    let header = gz_in.header().cloned();
+    if args.restore_name() && output_file.is_some() && args.output.is_none() {
+        if let Some(name) = header.as_ref().and_then(stored_name) {
+            output_file = Some(file.with_file_name(name));
+        }
+    }
 
-            let mut reader = OpenOptions::new()
-                .read(true)
-                .write(false)
-                .open(file)
-                .unwrap();
-            let mut writer = gz_writer.write(gz_out, Compression::new(compression_level));
-            io::copy(&mut reader, &mut writer).unwrap();
+    let Some(mut output) = open_output(args, &output_file, PRIVATE_MODE)? else {
+        return Ok(());
+    };
+    let data = first.as_slice().chain(&mut gz_in);
+    let bytes_out = copy_data(args, data, &mut output, LOCAL_EOL)
+        .and_then(|n| output.flush().map(|()| n))
+        .on(file)
+        .map_err(|failure| discard_output(&output_file, failure))? as i64;
+    drop(output);
+    warn_trailing(args, file, &gz_in);
+
+    if let Some(path) = &output_file {
+        let mtime = header
+            .as_ref()
+            .filter(|header| args.restore_time() && header.mtime() != 0)
+            .map(|header| SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime() as u64));
+        copy_metadata(&meta, path, mtime);
+    }
+
+    if args.verbose() {
+        let overhead = header.as_ref().map_or(0, overhead);
+        let ratio = ratio(bytes_out - (bytes_in - overhead), bytes_out);
+        report(args, file, &ratio, &output_file);
+    }
+    sync_output(args, &output_file)?;
+    remove_input(args, file);
+    Ok(())
+}
+
+fn compress_files(args: &Args) {
+    for file in args.inputs() {
+        let result = if is_stdin(&file) {
+            compress_stdin(args)
+        } else {
+            compress_file(args, &file)
+        };
+        if let Err(failure) = result {
+            fail(failure);
         }
     }
 }
+
+/// Under `-vv`, show the settings and header fields `file` is compressed
+/// with.
+fn trace_settings(args: &Args, file: &Path, header: &Header) {
+    if args.verbosity < 2 {
+        return;
+    }
+    eprintln!(
+        "{}: level {}, window bits {}, name {}, mtime {}",
+        display_name(file),
+        args.compression_level(),
+        WINDOW_BITS,
+        header.name.unwrap_or("-"),
+        header.mtime
+    );
+}
+
+/// Compress `input` into `output` as `args` ask, returning the number of
+/// bytes read and `output`.
+fn compress_data<R: Read, W: Write>(
+    args: &Args,
+    input: R,
+    output: W,
+    header: &Header,
+) -> io::Result<(u64, W)> {
+    let level = Compression::new(args.compression_level());
+    let threads = args.threads();
+    let parallel = args
+        .block_size()
+        .filter(|&block_size| parallel::blocks_in_flight(block_size, threads) > 1);
+    if let Some(block_size) = parallel {
+        let input: Box<dyn Read> = if args.ascii {
+            Box::new(Ascii::new(input, b"\n"))
+        } else {
+            Box::new(input)
+        };
+        return parallel::compress(
+            input,
+            output,
+            header,
+            level,
+            args.rsyncable,
+            block_size,
+            threads,
+        );
+    }
+
+    let mut writer =
+        Encoder::new(output, header, level, args.rsyncable)?.block_size(args.block_size());
+    let bytes_in = copy_data(args, input, &mut writer, b"\n")?;
+    // Write the CRC32/ISIZE trailer now rather than on drop, where any error
+    // would be silently discarded.
+    let mut output = writer.finish()?;
+    output.flush()?;
+    Ok((bytes_in, output))
+}
+
+fn compress_stdin(args: &Args) -> Result<(), Failure> {
+    if args.output.is_none() {
+        refuse_terminal(args, false);
+    }
+    let stdin = Path::new("-");
+    let Some(gz_out) = open_output(args, &args.output, NEW_FILE_MODE)? else {
+        return Ok(());
+    };
+    let gz_out = Counter::new(gz_out);
+    let comment = args.comment.as_deref().and_then(latin1);
+    let header = Header {
+        name: None,
+        comment: comment.as_deref(),
+        mtime: 0,
+    };
+    trace_settings(args, stdin, &header);
+    let (bytes_in, gz_out) = compress_data(args, io::stdin(), gz_out, &header)
+        .on(stdin)
+        .map_err(|failure| discard_output(&args.output, failure))?;
+
+    if args.verbose() {
+        let bytes_out = gz_out.count as i64;
+        let overhead = (header.len() as i64 + 8) * args.member_count(bytes_in) as i64;
+        let bytes_in = bytes_in as i64;
+        eprintln!("{}", ratio(bytes_in - (bytes_out - overhead), bytes_in));
+    }
+    sync_output(args, &args.output)
+}
+
+fn compress_file(args: &Args, file: &Path) -> Result<(), Failure> {
+    let file_name = file_name(file).on(file)?;
+    // Checked before opening, which would wait for a writer on a FIFO.
+    if refuse_input(args, file, &fs::metadata(file).on(file)?) {
+        return Ok(());
+    }
+    let input = File::open(file).on(file)?;
+    let meta = input.metadata().on(file)?;
+    let mtime = meta.modified().on(file)?;
+
+    if !args.to_stdout && !args.force && args.output.is_none() {
+        if let Some(suffix) = args.suffix_of(file_name) {
+            // Not a warning for the exit status, as in GNU gzip, and quiet
+            // under -r, where such files are expected.
+            if args.verbose() || (!args.recursive && !args.quiet) {
+                eprintln!(
+                    "gzip: {} already has {} suffix -- unchanged",
+                    file.display(),
+                    suffix
+                );
+            }
+            return Ok(());
+        }
+    }
+
+    let gz_out_file = if args.to_stdout {
+        None
+    } else if args.output.is_some() {
+        args.output.clone()
+    } else {
+        Some(file.with_file_name(format!("{}{}", file_name, args.suffix)))
+    };
+    let Some(gz_out) = open_output(args, &gz_out_file, PRIVATE_MODE)? else {
+        return Ok(());
+    };
+    let gz_out = Counter::new(gz_out);
+    let comment = args.comment.as_deref().and_then(latin1);
+    let header = Header {
+        name: args.save_name().then_some(file_name),
+        comment: comment.as_deref(),
+        mtime: if args.save_time() {
+            gzip_mtime(file, mtime)
+        } else {
+            0
+        },
+    };
+    trace_settings(args, file, &header);
+    let (bytes_in, gz_out) = compress_data(args, input, gz_out, &header)
+        .on(file)
+        .map_err(|failure| discard_output(&gz_out_file, failure))?;
+
+    if args.verbose() {
+        let bytes_out = gz_out.count as i64;
+        let overhead = (header.len() as i64 + 8) * args.member_count(bytes_in) as i64;
+        let bytes_in = bytes_in as i64;
+        let ratio = ratio(bytes_in - (bytes_out - overhead), bytes_in);
+        report(args, file, &ratio, &gz_out_file);
+    }
+    if let Some(path) = &gz_out_file {
+        copy_metadata(&meta, path, None);
+    }
+    sync_output(args, &gz_out_file)?;
+    remove_input(args, file);
+    Ok(())
+}
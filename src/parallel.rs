@@ -0,0 +1,89 @@
+//! Compressing `--blocks` on several threads at once.
+//!
+//! Each block is its own gzip member, so blocks can be compressed
+//! independently and written in order, as pigz does. The output is the same
+//! as `Encoder` writes with the same block size.
+
+use std::io::{self, Read, Write};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::{panic, thread};
+
+use flate2::Compression;
+
+use crate::encoder::{Encoder, Header};
+
+/// Most input bytes held at once, however many threads there are.
+const MAX_IN_FLIGHT: u64 = 64 * 1024 * 1024;
+
+/// How many `block_size` blocks to compress at once on `threads` threads:
+/// one per thread, but no more than fit in `MAX_IN_FLIGHT` bytes. With just
+/// one, threads gain nothing, and `Encoder` had better stream the input
+/// instead of holding a whole block.
+pub fn blocks_in_flight(block_size: NonZeroU64, threads: NonZeroUsize) -> usize {
+    (MAX_IN_FLIGHT / block_size.get()).clamp(1, threads.get() as u64) as usize
+}
+
+/// Compress `input` into `output` one `block_size` block per member, with
+/// `blocks_in_flight` blocks compressed at once. Returns the number of bytes
+/// read and `output`.
+pub fn compress<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    header: &Header,
+    level: Compression,
+    rsyncable: bool,
+    block_size: NonZeroU64,
+    threads: NonZeroUsize,
+) -> io::Result<(u64, W)> {
+    let in_flight = blocks_in_flight(block_size, threads);
+    let mut total = 0;
+    loop {
+        let mut blocks = Vec::with_capacity(in_flight);
+        let mut eof = false;
+        while !eof && blocks.len() < in_flight {
+            let mut block = Vec::new();
+            (&mut input)
+                .take(block_size.get())
+                .read_to_end(&mut block)?;
+            eof = (block.len() as u64) < block_size.get();
+            if !block.is_empty() {
+                total += block.len() as u64;
+                blocks.push(block);
+            }
+        }
+        // Empty input still makes one, empty, member.
+        if total == 0 {
+            blocks.push(Vec::new());
+        }
+
+        let members = thread::scope(|scope| {
+            let workers: Vec<_> = blocks
+                .iter()
+                .map(|block| scope.spawn(|| compress_block(block, header, level, rsyncable)))
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        for member in members {
+            output.write_all(&member)?;
+        }
+        if eof {
+            output.flush()?;
+            return Ok((total, output));
+        }
+    }
+}
+
+/// `block` as a complete gzip member.
+fn compress_block(
+    block: &[u8],
+    header: &Header,
+    level: Compression,
+    rsyncable: bool,
+) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(Vec::new(), header, level, rsyncable)?;
+    encoder.write_all(block)?;
+    encoder.finish()
+}